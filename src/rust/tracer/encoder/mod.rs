@@ -1,11 +1,13 @@
 use pyo3::prelude::*;
 use pyo3::wrap_pyfunction;
 use pyo3::types::PyBytes;
+use std::collections::HashMap;
+use std::mem;
 
 use super::gc::GarbageCollector;
 use super::span::{SpanReference, SpanStore};
 
-use rmp::encode::{write_array_len, write_map_len, write_str, write_u64, write_pfix, write_i64, write_f64, write_nil};
+use rmp::encode::{write_array_len, write_map_len, write_str, write_u64, write_uint, write_sint, write_pfix, write_i64, write_f64, write_nil};
 
 macro_rules! write_string {
     ($buf:ident, $value:expr) => {
@@ -45,8 +47,8 @@ macro_rules! write_int64 {
 
 fn encode_span(buf: &mut Vec<u8>, reference: &SpanReference) {
     let store = SpanStore::instance();
-    let map = store.map.lock().unwrap(); // TODO: This lock is not required :(
-    let span = map.get(&reference).unwrap();
+    let shard = store.shard(reference).lock().unwrap();
+    let span = shard.get(&reference).unwrap();
     
     let len = 9
         + if span.meta.len() > 0 { 1 } else { 0 }
@@ -113,6 +115,132 @@ fn encode_trace(buf: &mut Vec<u8>, trace: &Vec<SpanReference>) {
     }
 }
 
+// ---- v0.5 string-table encoding ----
+
+/// Dedup table used by the v0.5 encoder: maps an interned string to its
+/// index in `strings`, which is the order the string table is serialized in.
+/// Index 0 is reserved for the empty string.
+struct StringTable {
+    indices: HashMap<String, u32>,
+    strings: Vec<String>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable {
+            indices: HashMap::new(),
+            strings: vec![String::new()],
+        }
+    }
+
+    fn intern(&mut self, value: &str) -> u32 {
+        if value.is_empty() {
+            return 0;
+        }
+        if let Some(&index) = self.indices.get(value) {
+            return index;
+        }
+
+        let index = self.strings.len() as u32;
+        self.indices.insert(value.to_string(), index);
+        self.strings.push(value.to_string());
+        index
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_array_len(buf, self.strings.len() as u32).ok();
+        for s in self.strings.iter() {
+            write_str(buf, s).ok();
+        }
+    }
+}
+
+fn encode_span_v05(buf: &mut Vec<u8>, reference: &SpanReference, table: &mut StringTable) {
+    let store = SpanStore::instance();
+    let shard = store.shard(reference).lock().unwrap();
+    let span = shard.get(&reference).unwrap();
+
+    let service_idx = table.intern(span.service.as_str());
+    let name_idx = table.intern(span.name.as_str());
+    let resource_idx = table.intern(span.resource.as_str());
+    let type_idx = table.intern(span.span_type.as_str());
+
+    write_array_len(buf, 12).ok();
+
+    write_uint(buf, service_idx as u64).ok();
+    write_uint(buf, name_idx as u64).ok();
+    write_uint(buf, resource_idx as u64).ok();
+
+    write_u64(buf, span.trace_id).ok();
+    write_u64(buf, span.span_id).ok();
+    write_u64(buf, span.parent_id).ok();
+
+    // This is a fixed positional layout, so unlike the v0.4 map encoding a
+    // zero start/duration must stay an integer, not collapse to nil.
+    write_sint(buf, span.start).ok();
+    write_sint(buf, span.duration).ok();
+
+    write_pfix(buf, if span.error != 0 { 1 } else { 0 }).ok();
+
+    write_map_len(buf, span.meta.len() as u32).ok();
+    for (k, v) in &span.meta {
+        let key_idx = table.intern(k.as_str());
+        let value_idx = table.intern(v.as_str());
+        write_uint(buf, key_idx as u64).ok();
+        write_uint(buf, value_idx as u64).ok();
+    }
+
+    write_map_len(buf, span.metrics.len() as u32).ok();
+    for (k, v) in &span.metrics {
+        let key_idx = table.intern(k.as_str());
+        write_uint(buf, key_idx as u64).ok();
+        write_f64(buf, *v).ok();
+    }
+
+    write_uint(buf, type_idx as u64).ok();
+}
+
+fn encode_trace_v05(buf: &mut Vec<u8>, trace: &Vec<SpanReference>, table: &mut StringTable) {
+    write_array_len(buf, trace.len() as u32).ok();
+    for span in trace.iter() {
+        encode_span_v05(buf, span, table);
+    }
+}
+
+#[pyfunction]
+fn encode_v05(py: Python, traces: Vec<Vec<SpanReference>>) -> PyResult<&PyBytes> {
+    let gc = GarbageCollector::instance();
+
+    for trace in traces.iter() {
+        for s in trace.iter() {
+            gc.keep(s);
+        }
+    }
+
+    let mut table = StringTable::new();
+    let mut traces_buf = Vec::new();
+
+    py.allow_threads(|| {
+        write_array_len(&mut traces_buf, traces.len() as u32).ok();
+        for trace in traces.iter() {
+            encode_trace_v05(&mut traces_buf, trace, &mut table);
+        }
+    });
+
+    for trace in traces.iter() {
+        for s in trace.iter() {
+            gc.release(s);
+        }
+    }
+
+    let mut buf = Vec::new();
+    write_array_len(&mut buf, 2).ok();
+    table.write(&mut buf);
+    buf.extend_from_slice(&traces_buf);
+
+    Ok(PyBytes::new(py, &buf).into())
+}
+
 #[pyfunction]
 fn encode(py: Python, trace: Vec<SpanReference>) -> PyResult<&PyBytes> {
     let gc = GarbageCollector::instance();
@@ -139,8 +267,90 @@ fn encode(py: Python, trace: Vec<SpanReference>) -> PyResult<&PyBytes> {
     Ok(PyBytes::new(py, &buf).into())
 }
 
+// ---- Batch encoding with payload size limiting ----
+
+/// Writes a fixed-width (5 byte) msgpack array32 header and returns the
+/// offset of its length field, so the length can be patched in once the
+/// final element count of the payload is known.
+fn write_array32_placeholder(buf: &mut Vec<u8>) -> usize {
+    buf.push(0xdd);
+    let pos = buf.len();
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    pos
+}
+
+fn patch_array32_len(buf: &mut Vec<u8>, pos: usize, len: u32) {
+    buf[pos..pos + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+// write_array32_placeholder's marker byte plus its 4-byte length field.
+const ARRAY32_HEADER_LEN: usize = 5;
+
+#[pyfunction]
+fn encode_traces(py: Python, traces: Vec<Vec<SpanReference>>, max_size: usize) -> PyResult<(Vec<Py<PyBytes>>, usize)> {
+    let gc = GarbageCollector::instance();
+
+    for trace in traces.iter() {
+        for s in trace.iter() {
+            gc.keep(s);
+        }
+    }
+
+    let mut payloads = Vec::new();
+    let mut oversized = 0usize;
+
+    py.allow_threads(|| {
+        let mut current = Vec::new();
+        let mut current_pos = write_array32_placeholder(&mut current);
+        let mut current_count: u32 = 0;
+
+        for trace in traces.iter() {
+            let mut trace_buf = Vec::new();
+            encode_trace(&mut trace_buf, trace);
+
+            // A trace only fits a payload of its own once the array32
+            // header is accounted for; `current.len()` below already
+            // includes it, but a single oversize trace needs to be
+            // measured that way too so it's reported correctly.
+            if ARRAY32_HEADER_LEN + trace_buf.len() > max_size {
+                oversized += 1;
+            }
+
+            if current_count > 0 && current.len() + trace_buf.len() > max_size {
+                patch_array32_len(&mut current, current_pos, current_count);
+                payloads.push(mem::take(&mut current));
+                current_pos = write_array32_placeholder(&mut current);
+                current_count = 0;
+            }
+
+            current.extend_from_slice(&trace_buf);
+            current_count += 1;
+        }
+
+        if current_count > 0 {
+            patch_array32_len(&mut current, current_pos, current_count);
+            payloads.push(current);
+        }
+    });
+
+    for trace in traces.iter() {
+        for s in trace.iter() {
+            gc.release(s);
+        }
+    }
+
+    let payloads = payloads
+        .into_iter()
+        .map(|buf| PyBytes::new(py, &buf).into())
+        .collect();
+
+    Ok((payloads, oversized))
+}
+
 pub fn init_encoder(module: &PyModule) -> PyResult<()> {
     module.add_function(wrap_pyfunction!(encode, module)?)?;
+    module.add_function(wrap_pyfunction!(encode_v05, module)?)?;
+    module.add_function(wrap_pyfunction!(encode_traces, module)?)?;
 
     Ok(())
 }
@@ -1,6 +1,9 @@
+use chrono::{DateTime, NaiveDateTime};
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
 use pyo3::wrap_pyfunction;
 use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
 
 use std::mem;
 use std::sync::{Arc, Mutex, Once};
@@ -9,7 +12,7 @@ use std::sync::{Arc, Mutex, Once};
 macro_rules! span {
     ($reference:ident) => {
         SpanStore::instance()
-            .map
+            .shard(&$reference)
             .lock()
             .unwrap()
             .get(&$reference)
@@ -26,14 +29,14 @@ macro_rules! span {
 macro_rules! span_mut {
     ($reference:ident, $setter:ident, $value:ident) => {
         let store = SpanStore::instance();
-        let mut map = store.map.lock().unwrap();
-        let span = map.get_mut(&$reference).unwrap();
+        let mut shard = store.shard(&$reference).lock().unwrap();
+        let span = shard.get_mut(&$reference).unwrap();
         span.$setter($value);
     };
     ($reference:ident, $setter:ident, $key:ident, $value:ident) => {
         let store = SpanStore::instance();
-        let mut map = store.map.lock().unwrap();
-        let span = map.get_mut(&$reference).unwrap();
+        let mut shard = store.shard(&$reference).lock().unwrap();
+        let span = shard.get_mut(&$reference).unwrap();
         span.$setter($key, $value);
     };
 }
@@ -139,9 +142,14 @@ impl Span {
 
 pub type SpanReference = u32;
 
+// Number of independent map shards a `SpanStore` is split into. Must be a
+// power of two so shard selection is a cheap mask instead of a modulo.
+const SHARD_COUNT: usize = 16;
+const SHARD_MASK: u32 = (SHARD_COUNT as u32) - 1;
+
 #[derive(Clone)]
 pub struct SpanStore {
-    pub map: Arc<Mutex<HashMap<SpanReference, Span>>>, // TODO: This might not require a lock
+    shards: Arc<Vec<Mutex<HashMap<SpanReference, Span>>>>,
     count: Arc<Mutex<SpanReference>>,
     free: Arc<Mutex<VecDeque<SpanReference>>>,
 }
@@ -153,8 +161,11 @@ impl SpanStore {
         static ONCE: Once = Once::new();
         unsafe {
             ONCE.call_once(|| {
+                let shards = (0..SHARD_COUNT)
+                    .map(|_| Mutex::new(HashMap::new()))
+                    .collect();
                 let singleton = SpanStore {
-                    map: Arc::new(Mutex::new(HashMap::new())),
+                    shards: Arc::new(shards),
                     count: Arc::new(Mutex::new(0)),
                     free: Arc::new(Mutex::new(VecDeque::new())),
                 };
@@ -164,6 +175,13 @@ impl SpanStore {
         }
     }
 
+    // Only a function of the reference value, so it's safe for a span to be
+    // created on one shard and have its free-list entry recycled through a
+    // shared free list: `new_span` always looks the reference back up here.
+    pub fn shard(&self, reference: &SpanReference) -> &Mutex<HashMap<SpanReference, Span>> {
+        &self.shards[(*reference & SHARD_MASK) as usize]
+    }
+
     fn new_span(&self) -> SpanReference {
         let reference = match self.free.lock().unwrap().pop_back() {
             Some(n) => n,
@@ -174,8 +192,8 @@ impl SpanStore {
             }
         };
 
-        let mut map = self.map.lock().unwrap();
-        map.insert(
+        let mut shard = self.shard(&reference).lock().unwrap();
+        shard.insert(
             reference,
             Span {
                 service: String::new(),
@@ -197,15 +215,73 @@ impl SpanStore {
     }
 
     pub fn remove_span(&self, reference: &SpanReference) {
-        let mut map = self.map.lock().unwrap();
-        if map.contains_key(reference) {
-            map.remove(reference).unwrap();
+        let mut shard = self.shard(reference).lock().unwrap();
+        if shard.contains_key(reference) {
+            shard.remove(reference).unwrap();
+            drop(shard);
             let mut free = self.free.lock().unwrap();
             free.push_front(*reference);
         }
     }
 }
 
+// ---- Tag value conversion ----
+
+/// Describes how a raw string tag value should be parsed and where it
+/// should be stored on a `Span`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conversion {
+    Bytes,
+    Integer,
+    Float,
+    Boolean,
+    Timestamp,
+    TimestampFmt(String),
+    TimestampTZFmt(String),
+}
+
+impl FromStr for Conversion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '|');
+        let kind = parts.next().unwrap_or("");
+        let fmt = parts.next();
+
+        match (kind, fmt) {
+            ("asis", None) | ("bytes", None) | ("string", None) => Ok(Conversion::Bytes),
+            ("int", None) | ("integer", None) => Ok(Conversion::Integer),
+            ("float", None) => Ok(Conversion::Float),
+            ("bool", None) | ("boolean", None) => Ok(Conversion::Boolean),
+            ("timestamp", None) => Ok(Conversion::Timestamp),
+            // A timezone-aware strftime format (e.g. one ending in %z/%Z)
+            // needs offset-aware parsing, everything else is naive.
+            ("timestamp", Some(fmt)) if fmt.contains("%z") || fmt.contains("%Z") => {
+                Ok(Conversion::TimestampTZFmt(fmt.to_string()))
+            }
+            ("timestamp", Some(fmt)) => Ok(Conversion::TimestampFmt(fmt.to_string())),
+            _ => Err(format!("unknown tag conversion: {}", s)),
+        }
+    }
+}
+
+fn parse_timestamp_nanos(conversion: &Conversion, value: &str) -> Result<i64, String> {
+    let nanos = match conversion {
+        Conversion::Timestamp => DateTime::parse_from_rfc3339(value)
+            .map_err(|e| e.to_string())?
+            .timestamp_nanos_opt(),
+        Conversion::TimestampFmt(fmt) => NaiveDateTime::parse_from_str(value, fmt)
+            .map_err(|e| e.to_string())?
+            .timestamp_nanos_opt(),
+        Conversion::TimestampTZFmt(fmt) => DateTime::parse_from_str(value, fmt)
+            .map_err(|e| e.to_string())?
+            .timestamp_nanos_opt(),
+        _ => unreachable!(),
+    };
+
+    nanos.ok_or_else(|| format!("timestamp out of range: {}", value))
+}
+
 // ---- Python Interface ----
 
 #[pyfunction]
@@ -348,6 +424,65 @@ fn del_metrics(reference: SpanReference, key: String) {
     span_mut!(reference, del_metrics, key);
 }
 
+/// Parses `value` per `conversion` and stores it on the span at `key`, in
+/// `meta` or `metrics` depending on the conversion.
+///
+/// Note: `metrics` is `f64`, so the timestamp conversions' nanosecond value
+/// only keeps ~52 bits of precision rather than the full `i64` range — for
+/// epoch-nanosecond magnitudes that rounds to the nearest few hundred
+/// nanoseconds.
+#[pyfunction]
+fn set_tag(reference: SpanReference, key: String, value: String, conversion: String) -> PyResult<()> {
+    if value.is_empty() {
+        return Ok(());
+    }
+
+    let conversion = Conversion::from_str(&conversion).map_err(PyValueError::new_err)?;
+
+    match conversion {
+        Conversion::Bytes => {
+            span_mut!(reference, set_meta, key, value);
+        }
+        Conversion::Integer => {
+            let number: i64 = value
+                .parse()
+                .map_err(|_| PyValueError::new_err(format!("invalid integer value: {}", value)))?;
+            let number = number as f64;
+            span_mut!(reference, set_metrics, key, number);
+        }
+        Conversion::Float => {
+            let number: f64 = value
+                .parse()
+                .map_err(|_| PyValueError::new_err(format!("invalid float value: {}", value)))?;
+            span_mut!(reference, set_metrics, key, number);
+        }
+        Conversion::Boolean => {
+            let number = match value.to_lowercase().as_str() {
+                "true" | "1" | "yes" => 1.0,
+                "false" | "0" | "no" => 0.0,
+                _ => {
+                    return Err(PyValueError::new_err(format!(
+                        "invalid boolean value: {}",
+                        value
+                    )))
+                }
+            };
+            span_mut!(reference, set_metrics, key, number);
+        }
+        conversion @ (Conversion::Timestamp
+        | Conversion::TimestampFmt(_)
+        | Conversion::TimestampTZFmt(_)) => {
+            let nanos = parse_timestamp_nanos(&conversion, &value).map_err(PyValueError::new_err)?;
+            // metrics is f64, so this loses precision below ~f64 mantissa
+            // resolution at epoch-nanosecond magnitudes (see doc comment above).
+            let nanos = nanos as f64;
+            span_mut!(reference, set_metrics, key, nanos);
+        }
+    }
+
+    Ok(())
+}
+
 #[pyfunction]
 fn get_span_type(reference: SpanReference) -> PyResult<String> {
     Ok(span!(reference, get_span_type))
@@ -392,6 +527,7 @@ pub fn init_span(module: &PyModule) -> PyResult<()> {
     module.add_function(wrap_pyfunction!(get_metrics, module)?)?;
     module.add_function(wrap_pyfunction!(set_metrics, module)?)?;
     module.add_function(wrap_pyfunction!(del_metrics, module)?)?;
+    module.add_function(wrap_pyfunction!(set_tag, module)?)?;
 
     module.add_function(wrap_pyfunction!(get_span_type, module)?)?;
     module.add_function(wrap_pyfunction!(set_span_type, module)?)?;